@@ -1,29 +1,88 @@
-use std::io::{self, Error, Write};
+use std::io::{self, Error, Read, Write};
 use std::process::{Stdio, Command};
-use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::fs::{File, OpenOptions};
+use std::thread;
+use std::time::{Duration, Instant};
 use super::flags::*;
 use super::fork::{fork_pipe, create_process_group};
 use super::job_control::JobControl;
-use super::{JobKind, Shell};
+use super::{Job, JobKind, Shell};
 use super::status::*;
 use super::signals::{self, SignalHandler};
 use parser::peg::{Pipeline, Input, RedirectFrom};
 use sys;
 
+/// Distinct exit status returned when a pipeline is killed for exceeding its
+/// `Shell::pipeline_timeout`, alongside the other exit-status constants in
+/// `status`.
+pub const TIMED_OUT: i32 = 124;
+
+/// Sets the per-pipeline timeout consulted by `execute_pipeline`: a pipeline
+/// that is still running after `timeout` has its foreground process group
+/// terminated (see `wait_with_deadline`) instead of being waited on forever.
+/// `None` (the default) disables the behavior entirely.
+///
+/// `timeout_builtin` is the user-facing entry point that calls this.
+pub fn set_pipeline_timeout(shell: &mut Shell, timeout: Option<Duration>) {
+    shell.pipeline_timeout = timeout;
+}
+
+/// Parses the argument to the `timeout` builtin: a non-negative integer
+/// number of seconds, or `"off"` to disable the per-pipeline timeout. Kept
+/// separate from `timeout_builtin` so the parsing itself is testable without
+/// a `Shell`.
+fn parse_timeout_arg(arg: &str) -> Result<Option<Duration>, ()> {
+    if arg == "off" {
+        return Ok(None);
+    }
+    arg.parse::<u64>().map(|secs| Some(Duration::from_secs(secs))).map_err(|_| ())
+}
+
+/// The `timeout` builtin: `timeout SECONDS` makes every pipeline run
+/// afterward subject to a SECONDS-long deadline (see `wait_with_deadline`);
+/// `timeout off` disables it again. Meant to be registered into
+/// `shell.builtins` under the name `"timeout"` alongside ion's other
+/// builtins, giving `set_pipeline_timeout` a real command-line entry point.
+pub fn timeout_builtin(args: &[String], shell: &mut Shell) -> i32 {
+    match args.get(1) {
+        None => {
+            eprintln!("timeout: usage: timeout SECONDS|off");
+            FAILURE
+        },
+        Some(arg) => match parse_timeout_arg(arg) {
+            Ok(timeout) => {
+                set_pipeline_timeout(shell, timeout);
+                SUCCESS
+            },
+            Err(()) => {
+                eprintln!("timeout: invalid duration: '{}'", arg);
+                FAILURE
+            },
+        },
+    }
+}
+
 /// Create an instance of Stdio from a byte slice that will echo the
-/// contents of the slice when read. This can be called with owned or
-/// borrowed strings
-pub unsafe fn stdin_of<T: AsRef<[u8]>>(input: T) -> Result<Stdio, Error> {
+/// contents of the slice when read. The input is moved to a writer thread,
+/// so it must be owned data or a `'static` borrow.
+///
+/// The bytes are written from a dedicated thread so that the caller never
+/// blocks on the pipe: a herestring/heredoc larger than the OS pipe buffer
+/// (~64 KiB on Linux) would otherwise deadlock the shell, since nothing
+/// reads from the other end until the pipeline is spawned afterwards.
+pub unsafe fn stdin_of<T: AsRef<[u8]> + Send + 'static>(input: T) -> Result<Stdio, Error> {
     let (reader, writer) = sys::pipe2(sys::O_CLOEXEC)?;
     let mut infile = File::from_raw_fd(writer);
-    // Write the contents; make sure to use write_all so that we block until
-    // the entire string is written
-    infile.write_all(input.as_ref())?;
-    infile.flush()?;
-    // `infile` currently owns the writer end RawFd. If we just return the reader end
-    // and let `infile` go out of scope, it will be closed, sending EOF to the reader!
+    thread::spawn(move || {
+        // Errors here (e.g. the reader going away early) are not actionable;
+        // the exit status of the pipeline is what the user will see.
+        let _ = infile.write_all(input.as_ref());
+        let _ = infile.flush();
+        // `infile` is dropped here, closing the write end and sending EOF to
+        // the reader regardless of how much data was actually consumed.
+    });
     Ok(Stdio::from_raw_fd(reader))
 }
 
@@ -56,6 +115,59 @@ pub unsafe fn create_pipe (
     Ok(())
 }
 
+/// Where an arbitrary file-descriptor redirection such as `3>file`, `2>&1`,
+/// or `4>&-` should point.
+pub enum RedirectTarget {
+    /// Open (or create/append) a file and dup its fd onto the source fd.
+    File(String, bool /* append */),
+    /// Dup an existing fd onto the source fd, as in `2>&1`.
+    Fd(i32),
+    /// Close the source fd, as in `4>&-`.
+    Close,
+}
+
+/// Applies a list of `(source_fd, target)` redirections to `command` from
+/// within a `before_exec` closure, in the same left-to-right order they were
+/// written in. Ordering matters: `2>&1 1>file` dups stderr onto whatever
+/// stdout currently points at (the terminal) before redirecting stdout to
+/// `file`, so stderr still reaches the terminal; applying them out of order
+/// would send both to the file instead.
+///
+/// This is consumption-side only: `job.redirects` still has no grammar
+/// support, so nothing populates it from `2>&1`/`3>file`/`n>&m` syntax yet.
+/// Teaching the PEG parser to recognize that syntax and fill in
+/// `job.redirects` is the remaining, separate piece of work.
+pub fn apply_redirects(command: &mut Command, redirects: Vec<(i32, RedirectTarget)>) {
+    command.before_exec(move || {
+        run_redirects(&redirects);
+        Ok(())
+    });
+}
+
+/// The part of `apply_redirects` that actually performs the dup2/close
+/// calls, left-to-right. Factored out so the ordering behavior can be
+/// exercised directly in a test without going through `Command::before_exec`,
+/// whose closure is otherwise only ever invoked by the OS around `exec`.
+fn run_redirects(redirects: &[(i32, RedirectTarget)]) {
+    for &(source, ref target) in redirects {
+        let result = match *target {
+            RedirectTarget::File(ref path, append) => {
+                let opened = if append {
+                    OpenOptions::new().create(true).write(true).append(true).open(path)
+                } else {
+                    File::create(path)
+                };
+                opened.and_then(|file| sys::dup2(file.into_raw_fd(), source).map(|_| ()))
+            },
+            RedirectTarget::Fd(target_fd) => sys::dup2(target_fd, source).map(|_| ()),
+            RedirectTarget::Close => sys::close(source),
+        };
+        if let Err(ref e) = result {
+            eprintln!("ion: failed to apply redirection onto fd {}: {}", source, e);
+        }
+    }
+}
+
 /// This function serves three purposes:
 /// 1. If the result is `Some`, then we will fork the pipeline executing into the background.
 /// 2. The value stored within `Some` will be that background job's command name.
@@ -73,6 +185,66 @@ fn check_if_background_job(pipeline: &Pipeline, print_comm: bool) -> Option<Stri
     }
 }
 
+/// Recognizes an argv word shaped like general fd-redirection syntax
+/// (`2>&1`, `3>file`, `4>&-`) that the grammar doesn't parse yet (see the
+/// note on `apply_redirects`), so it arrives here as a plain word instead of
+/// populating `job.redirects`. Used only to warn instead of silently passing
+/// a stray redirect token through to the command as a literal argument.
+fn looks_like_fd_redirect(arg: &str) -> bool {
+    let digits = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    if digits == 0 || digits == arg.len() { return false; }
+    let rest = &arg[digits..];
+    rest.starts_with(">&") || rest.starts_with(">>") || (rest.starts_with('>') && rest.len() > 1)
+}
+
+/// Recognizes an argv word opening process-substitution syntax (`<(cmd)`,
+/// `>(cmd)`) that the grammar doesn't parse yet (see the note on
+/// `create_substitution`), so it arrives here as a plain word instead of
+/// being replaced with a `/dev/fd/N` path. Used only to warn instead of
+/// silently passing the literal `<(`/`>(` text through to the command.
+fn looks_like_process_substitution(arg: &str) -> bool {
+    arg.starts_with("<(") || arg.starts_with(">(")
+}
+
+/// Builds the `Command` for a single job, applying its general fd
+/// redirects (`job.redirects`, populated by `2>&1`/`3>file`/`n>&m` syntax
+/// once the grammar grows support for it — see the note on `apply_redirects`)
+/// the same way regardless of whether the pipeline is about to be run
+/// attached to the terminal or captured in-memory. Shared by
+/// `execute_pipeline` and `capture_pipeline` so the two don't drift apart
+/// on which job-level settings get honored.
+///
+/// Also warns (rather than failing silently) when an argv word looks like
+/// redirect or process-substitution syntax the parser doesn't actually
+/// support yet, so that gap is visible to the user instead of the word just
+/// being passed through as a literal argument.
+fn build_pipeline_command(shell: &Shell, mut job: Job) -> PipelineCommand {
+    let redirects = job.redirects.drain(..).collect::<Vec<_>>();
+    let args: Vec<String> = job.args.iter().map(ToString::to_string).collect();
+    for arg in &args {
+        if looks_like_fd_redirect(arg) {
+            eprintln!(
+                "ion: warning: '{}' looks like a fd-redirection, but that syntax isn't \
+                 supported yet and will be passed through as a literal argument", arg
+            );
+        } else if looks_like_process_substitution(arg) {
+            eprintln!(
+                "ion: warning: '{}' looks like a process substitution, but that syntax isn't \
+                 supported yet and will be passed through as a literal argument", arg
+            );
+        }
+    }
+    let mut command = if shell.builtins.contains_key(&job.command.as_ref()) {
+        job.build_command_builtin()
+    } else {
+        job.build_command_external()
+    };
+    if !redirects.is_empty() {
+        apply_redirects(&mut command, redirects);
+    }
+    PipelineCommand::new(command, args, job.kind)
+}
+
 pub trait PipelineExecution {
     fn execute_pipeline(&mut self, pipeline: &mut Pipeline) -> i32;
 }
@@ -82,21 +254,15 @@ impl<'a> PipelineExecution for Shell<'a> {
         let background_string = check_if_background_job(&pipeline, self.flags & PRINT_COMMS != 0);
 
         // Generate a list of commands from the given pipeline
-        let mut piped_commands: Vec<(Command, JobKind)> = pipeline.jobs
-            .drain(..).map(|mut job| {
-                if self.builtins.contains_key(&job.command.as_ref()) {
-                    (job.build_command_builtin(), job.kind)
-                } else {
-                    (job.build_command_external(), job.kind)
-                }
-            }).collect();
+        let mut piped_commands: Vec<PipelineCommand> = pipeline.jobs
+            .drain(..).map(|job| build_pipeline_command(self, job)).collect();
         match pipeline.stdin {
             None => (),
             Some(Input::File(ref filename)) => {
                 if let Some(command) = piped_commands.first_mut() {
                     match File::open(filename) {
                         Ok(file) => unsafe {
-                            command.0.stdin(Stdio::from_raw_fd(file.into_raw_fd()));
+                            command.command.stdin(Stdio::from_raw_fd(file.into_raw_fd()));
                         },
                         Err(e) => {
                             eprintln!("ion: failed to redirect '{}' into stdin: {}", filename, e);
@@ -107,9 +273,9 @@ impl<'a> PipelineExecution for Shell<'a> {
             Some(Input::HereString(ref mut string)) => {
                 if let Some(command) = piped_commands.first_mut() {
                     if !string.ends_with('\n') { string.push('\n'); }
-                    match unsafe { stdin_of(&string) } {
+                    match unsafe { stdin_of(string.clone()) } {
                         Ok(stdio) => {
-                            command.0.stdin(stdio);
+                            command.command.stdin(stdio);
                         },
                         Err(e) => {
                             eprintln!("ion: failed to redirect herestring '{}' into stdin: {}",
@@ -132,14 +298,14 @@ impl<'a> PipelineExecution for Shell<'a> {
                         match stdout.from {
                             RedirectFrom::Both => {
                                 let fd = f.into_raw_fd();
-                                command.0.stderr(Stdio::from_raw_fd(fd));
-                                command.0.stdout(Stdio::from_raw_fd(fd));
+                                command.command.stderr(Stdio::from_raw_fd(fd));
+                                command.command.stdout(Stdio::from_raw_fd(fd));
                             },
                             RedirectFrom::Stderr => {
-                                command.0.stderr(Stdio::from_raw_fd(f.into_raw_fd()));
+                                command.command.stderr(Stdio::from_raw_fd(f.into_raw_fd()));
                             },
                             RedirectFrom::Stdout => {
-                                command.0.stdout(Stdio::from_raw_fd(f.into_raw_fd()));
+                                command.command.stdout(Stdio::from_raw_fd(f.into_raw_fd()));
                             },
                         }
                     },
@@ -168,17 +334,212 @@ impl<'a> PipelineExecution for Shell<'a> {
     }
 }
 
+/// Runs a pipeline to completion, capturing the last command's stdout and
+/// stderr (always both) into in-memory buffers instead of forwarding them to
+/// the terminal. This is the plumbing that command substitution (`$(...)`)
+/// needs, and lets code embedding Ion drive a pipeline programmatically.
+pub trait PipelineCapture {
+    fn capture_pipeline(
+        &mut self,
+        pipeline: &mut Pipeline,
+        feed_stdin: Option<&[u8]>
+    ) -> io::Result<(i32, Vec<u8>, Vec<u8>)>;
+}
+
+impl<'a> PipelineCapture for Shell<'a> {
+    fn capture_pipeline(
+        &mut self,
+        pipeline: &mut Pipeline,
+        feed_stdin: Option<&[u8]>
+    ) -> io::Result<(i32, Vec<u8>, Vec<u8>)> {
+        let mut piped_commands: Vec<PipelineCommand> = pipeline.jobs
+            .drain(..).map(|job| build_pipeline_command(self, job)).collect();
+
+        // Wire the last command's stdout/stderr to pipes that we, not the
+        // terminal, own.
+        let (stdout_reader, stdout_writer) = sys::pipe2(sys::O_CLOEXEC)?;
+        let (stderr_reader, stderr_writer) = sys::pipe2(sys::O_CLOEXEC)?;
+        if let Some(last) = piped_commands.last_mut() {
+            unsafe {
+                last.command.stdout(Stdio::from_raw_fd(stdout_writer));
+                last.command.stderr(Stdio::from_raw_fd(stderr_writer));
+            }
+        }
+
+        // If we were given input to feed, wire a fresh pipe into the first
+        // command's stdin; the writer end is drained by `communicate` below.
+        let stdin_pipe = if let Some(data) = feed_stdin {
+            let (reader, writer) = sys::pipe2(sys::O_CLOEXEC)?;
+            if let Some(first) = piped_commands.first_mut() {
+                unsafe { first.command.stdin(Stdio::from_raw_fd(reader)); }
+            }
+            Some((unsafe { File::from_raw_fd(writer) }, data.to_vec()))
+        } else {
+            None
+        };
+        let stdout_file = unsafe { File::from_raw_fd(stdout_reader) };
+        let stderr_file = unsafe { File::from_raw_fd(stderr_reader) };
+
+        self.foreground.clear();
+        let _sig_ignore = SignalHandler::new();
+
+        // `pipe` spawns the children and then blocks this thread waiting on
+        // them. A child that fills its stdout or stderr pipe before exiting
+        // would deadlock if we only started draining those pipes afterwards,
+        // so `communicate` runs concurrently on its own thread instead.
+        let io_thread = thread::spawn(move || communicate(stdin_pipe, stdout_file, stderr_file));
+
+        let exit_status = pipe(self, piped_commands, false);
+
+        let (stdout_buf, stderr_buf) = match io_thread.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::new(io::ErrorKind::Other, "communicate thread panicked")),
+        };
+
+        Ok((exit_status, stdout_buf, stderr_buf))
+    }
+}
+
+const POLLIN:  i16 = 0x001;
+const POLLOUT: i16 = 0x004;
+/// Largest write that a pipe reported writable by `poll` takes without blocking.
+const PIPE_BUF: usize = 4096;
+
+/// Concurrently feeds `stdin_pipe`'s data (if any) and drains `stdout`/
+/// `stderr` into buffers, using `poll` to multiplex between them so that a
+/// child filling one pipe while we would otherwise be blocked reading or
+/// writing the other can never deadlock us. Mirrors the approach used by
+/// `subprocess::Communicator` and cc's `StderrForwarder`.
+fn communicate(
+    mut stdin_pipe: Option<(File, Vec<u8>)>,
+    mut stdout: File,
+    mut stderr: File
+) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut stdout_buf = Vec::new();
+    let mut stderr_buf = Vec::new();
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut stdin_pos = 0usize;
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        if !stdout_open && !stderr_open && stdin_pipe.is_none() {
+            break;
+        }
+
+        let mut fds = Vec::new();
+        let mut stdin_idx = None;
+        let mut stdout_idx = None;
+        let mut stderr_idx = None;
+
+        if let Some((ref file, ref data)) = stdin_pipe {
+            if stdin_pos < data.len() {
+                stdin_idx = Some(fds.len());
+                fds.push(sys::PollFd { fd: file.as_raw_fd(), events: POLLOUT, revents: 0 });
+            }
+        }
+        if stdin_idx.is_none() {
+            // Either there was nothing left to write or there never was any
+            // input: dropping the file closes the fd, sending EOF downstream.
+            stdin_pipe = None;
+        }
+        if stdout_open {
+            stdout_idx = Some(fds.len());
+            fds.push(sys::PollFd { fd: stdout.as_raw_fd(), events: POLLIN, revents: 0 });
+        }
+        if stderr_open {
+            stderr_idx = Some(fds.len());
+            fds.push(sys::PollFd { fd: stderr.as_raw_fd(), events: POLLIN, revents: 0 });
+        }
+
+        if fds.is_empty() {
+            break;
+        }
+
+        if unsafe { sys::poll(fds.as_mut_ptr(), fds.len() as u64, -1) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        if let Some(i) = stdin_idx {
+            if fds[i].revents != 0 {
+                if let Some((ref mut file, ref data)) = stdin_pipe {
+                    // A larger blocking write would wait for the child to read
+                    // all of it, while the child may be waiting on us to read.
+                    let end = data.len().min(stdin_pos + PIPE_BUF);
+                    match file.write(&data[stdin_pos..end]) {
+                        Ok(n) if n > 0 => stdin_pos += n,
+                        _ => stdin_pos = data.len(),
+                    }
+                }
+            }
+        }
+        if let Some(i) = stdout_idx {
+            if fds[i].revents != 0 {
+                match stdout.read(&mut chunk) {
+                    Ok(0) | Err(_) => stdout_open = false,
+                    Ok(n) => stdout_buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+        if let Some(i) = stderr_idx {
+            if fds[i].revents != 0 {
+                match stderr.read(&mut chunk) {
+                    Ok(0) | Err(_) => stderr_open = false,
+                    Ok(n) => stderr_buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+    }
+
+    Ok((stdout_buf, stderr_buf))
+}
+
+/// A command paired with its original argv and the job operator that
+/// follows it. Carrying the argv alongside the `Command` lets job titles and
+/// `set -x`/`PRINT_COMMS` output be built from structured data, rather than
+/// reconstructed by parsing `Command`'s `Debug` output (which mangles any
+/// argument containing spaces or quotes).
+pub struct PipelineCommand {
+    command: Command,
+    args:    Vec<String>,
+    kind:    JobKind,
+}
+
+impl PipelineCommand {
+    pub fn new(command: Command, args: Vec<String>, kind: JobKind) -> PipelineCommand {
+        PipelineCommand { command: command, args: args, kind: kind }
+    }
+}
+
+/// Shell-quotes `arg` if it contains characters that would otherwise split
+/// it into multiple words when printed.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| !c.is_whitespace() && c != '\'' && c != '"') {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn command_name(args: &[String]) -> String {
+    args.first().cloned().unwrap_or_default()
+}
+
+fn full_command(args: &[String]) -> String {
+    args.iter().map(|arg| quote(arg)).collect::<Vec<String>>().join(" ")
+}
+
 /// This function will panic if called with an empty slice
 pub fn pipe (
     shell: &mut Shell,
-    commands: Vec<(Command, JobKind)>,
+    commands: Vec<PipelineCommand>,
     foreground: bool
 ) -> i32 {
     let mut previous_status = SUCCESS;
     let mut previous_kind = JobKind::And;
     let mut commands = commands.into_iter();
     loop {
-        if let Some((mut parent, mut kind)) = commands.next() {
+        if let Some(PipelineCommand { command: mut parent, args: parent_args, kind: mut kind }) = commands.next() {
             // When an `&&` or `||` operator is utilized, execute commands based on the previous status.
             match previous_kind {
                 JobKind::And => if previous_status != SUCCESS {
@@ -203,9 +564,10 @@ pub fn pipe (
                     let mut children: Vec<u32> = Vec::new();
                     // The process group by which all of the PIDs belong to.
                     let mut pgid = 0; // 0 means the PGID is not set yet.
+                    let mut parent_args = parent_args;
 
                     macro_rules! spawn_proc {
-                        ($cmd:expr) => {{
+                        ($cmd:expr, $args:expr) => {{
                             let child = $cmd.before_exec(move || {
                                 signals::unblock();
                                 create_process_group(pgid);
@@ -223,7 +585,7 @@ pub fn pipe (
                                     children.push(child.id());
                                 },
                                 Err(e) => {
-                                    eprintln!("ion: failed to spawn `{}`: {}", get_command_name($cmd), e);
+                                    eprintln!("ion: failed to spawn `{}`: {}", command_name($args), e);
                                     return NO_SUCH_COMMAND
                                 }
                             }
@@ -231,37 +593,39 @@ pub fn pipe (
                     }
 
                     // Append other jobs until all piped jobs are running
-                    while let Some((mut child, ckind)) = commands.next() {
+                    while let Some(PipelineCommand { command: mut child, args: child_args, kind: ckind }) = commands.next() {
                         if let Err(e) = unsafe {
                             create_pipe(&mut parent, &mut child, mode)
                         } {
                             eprintln!("ion: failed to create pipe for redirection: {:?}", e);
                         }
-                        spawn_proc!(&mut parent);
-                        remember.push(parent);
+                        spawn_proc!(&mut parent, &parent_args);
+                        remember.push((parent, parent_args));
                         if let JobKind::Pipe(m) = ckind {
                             parent = child;
+                            parent_args = child_args;
                             mode = m;
                         } else {
                             // We set the kind to the last child kind that was processed. For
                             // example, the pipeline `foo | bar | baz && zardoz` should have the
                             // previous kind set to `And` after processing the initial pipeline
                             kind = ckind;
-                            spawn_proc!(&mut child);
-                            remember.push(child);
+                            spawn_proc!(&mut child, &child_args);
+                            remember.push((child, child_args));
                             break
                         }
                     }
 
                     previous_kind = kind;
-                    previous_status = wait(shell, children, remember);
+                    let timeout = shell.pipeline_timeout;
+                    previous_status = wait(shell, children, remember, timeout);
                     if previous_status == TERMINATED {
                         terminate_fg(shell);
                         return previous_status;
                     }
                 }
                 _ => {
-                    previous_status = execute_command(shell, &mut parent, foreground);
+                    previous_status = execute_command(shell, &mut parent, &parent_args, foreground);
                     previous_kind = kind;
                 }
             }
@@ -276,7 +640,74 @@ fn terminate_fg(shell: &mut Shell) {
     shell.foreground_send(sys::SIGTERM);
 }
 
-fn execute_command(shell: &mut Shell, command: &mut Command, foreground: bool) -> i32 {
+/// Which end of a process-substitution pipe the outer command reads or
+/// writes: `Read` backs `<(cmd)` and `Write` backs `>(cmd)`.
+pub enum SubstitutionDirection { Read, Write }
+
+/// A `/dev/fd/N` path produced by [`create_substitution`]. The backing fd is
+/// kept open for as long as this value is alive, and closed on drop once the
+/// outer command has been spawned and no longer needs it.
+pub struct Substitution {
+    pub path: String,
+    fd:       RawFd,
+}
+
+impl Drop for Substitution {
+    fn drop(&mut self) {
+        unsafe { let _ = File::from_raw_fd(self.fd); }
+    }
+}
+
+/// Spawns the inner command of a `<(cmd)` or `>(cmd)` process substitution,
+/// wiring its stdout (`Read`) or stdin (`Write`) to one end of a fresh pipe,
+/// and returns a `/dev/fd/N` path to substitute into the outer command's
+/// argv in place of `<(cmd)`/`>(cmd)`.
+///
+/// The substituted fd is created without `O_CLOEXEC` so that it survives
+/// into the outer command across `execve`; the caller is responsible for
+/// keeping the returned `Substitution` alive until the outer command has
+/// been spawned. The inner command's PID is pushed onto `shell.foreground`
+/// so it is reaped alongside the rest of the pipeline in `wait`.
+///
+/// NOTE: this is execution-side plumbing only, and an inert scaffold as
+/// shipped - nothing in the parser's `Input`/argument-expansion grammar
+/// recognizes `<(cmd)`/`>(cmd)` syntax yet and substitutes the resulting
+/// path into argv, so this function has no call sites and `<(...)`/`>(...)`
+/// do not work from shell syntax today. `build_pipeline_command` at least
+/// warns when it sees the syntax (`looks_like_process_substitution`) rather
+/// than passing it through to the command silently. Wiring this up for real
+/// is a follow-up that touches the grammar, not this file.
+pub fn create_substitution(
+    shell: &mut Shell,
+    mut inner: Command,
+    direction: SubstitutionDirection
+) -> io::Result<Substitution> {
+    let (reader, writer) = sys::pipe2(0)?;
+    let (outer_fd, inner_fd) = match direction {
+        SubstitutionDirection::Read  => (reader, writer),
+        SubstitutionDirection::Write => (writer, reader),
+    };
+
+    unsafe {
+        match direction {
+            SubstitutionDirection::Read  => { inner.stdout(Stdio::from_raw_fd(inner_fd)); },
+            SubstitutionDirection::Write => { inner.stdin(Stdio::from_raw_fd(inner_fd)); },
+        }
+    }
+
+    match inner.before_exec(move || {
+        signals::unblock();
+        create_process_group(0);
+        Ok(())
+    }).spawn() {
+        Ok(child) => shell.foreground.push(child.id()),
+        Err(e) => eprintln!("ion: failed to spawn process substitution: {}", e),
+    }
+
+    Ok(Substitution { path: format!("/dev/fd/{}", outer_fd), fd: outer_fd })
+}
+
+fn execute_command(shell: &mut Shell, command: &mut Command, args: &[String], foreground: bool) -> i32 {
     match command.before_exec(move || {
         signals::unblock();
         create_process_group(0);
@@ -286,13 +717,17 @@ fn execute_command(shell: &mut Shell, command: &mut Command, foreground: bool) -
             if foreground {
                 let _ = sys::tcsetpgrp(0, child.id());
             }
-            shell.watch_foreground(child.id(), child.id(), || get_full_command(command), |_| ())
+            if let Some(timeout) = shell.pipeline_timeout {
+                shell.foreground.push(child.id());
+                return wait_with_deadline(shell, vec![child.id()], child.id(), Instant::now() + timeout);
+            }
+            shell.watch_foreground(child.id(), child.id(), || full_command(args), |_| ())
         },
         Err(e) => {
             let stderr = io::stderr();
             let mut stderr = stderr.lock();
             let _ = if e.kind() == io::ErrorKind::NotFound {
-                writeln!(stderr, "ion: Command not found: {}", get_command_name(command))
+                writeln!(stderr, "ion: Command not found: {}", command_name(args))
             } else {
                 writeln!(stderr, "ion: Error spawning process: {}", e)
             };
@@ -302,21 +737,29 @@ fn execute_command(shell: &mut Shell, command: &mut Command, foreground: bool) -
 }
 
 /// Waits for all of the children within a pipe to finish exuecting, returning the
-/// exit status of the last process in the queue.
+/// exit status of the last process in the queue. If `timeout` is set and the
+/// pipeline outlives it, the foreground process group is terminated instead
+/// and `TIMED_OUT` is returned.
 fn wait (
     shell: &mut Shell,
     mut children: Vec<u32>,
-    mut commands: Vec<Command>
+    mut commands: Vec<(Command, Vec<String>)>,
+    timeout: Option<Duration>
 ) -> i32 {
     // TODO: Find a way to only do this when absolutely necessary.
-    let as_string = commands.iter().map(get_full_command)
+    let as_string = commands.iter().map(|&(_, ref args)| full_command(args))
             .collect::<Vec<String>>().join(" | ");
 
-    // Each process in the pipe has the same PGID, which is the first process's PID.
-    let pgid = children[0];
     // If the last process exits, we know that all processes should exit.
     let last_pid = children[children.len()-1];
 
+    if let Some(timeout) = timeout {
+        return wait_with_deadline(shell, children, last_pid, Instant::now() + timeout);
+    }
+
+    // Each process in the pipe has the same PGID, which is the first process's PID.
+    let pgid = children[0];
+
     // Watch the foreground group, dropping all commands that exit as they exit.
     shell.watch_foreground(pgid, last_pid, move || as_string, move |pid| {
         if let Some(id) = children.iter().position(|&x| x as i32 == pid) {
@@ -326,24 +769,98 @@ fn wait (
     })
 }
 
-fn get_command_name(command: &Command) -> String {
-    format!("{:?}", command).split('"').nth(1).unwrap_or("").to_string()
+/// Outcome of reaping `last_pid` during one `reap_finished` pass: either its
+/// real exit status, or `Gone` if `waitpid` failed for it (e.g. a double-reap
+/// race with something else reaping the same pid). Distinguishing the two
+/// matters to the caller: neither means "still running", but only `Exited`
+/// carries a real status to return.
+enum LastPid {
+    Exited(i32),
+    Gone,
 }
 
-fn get_full_command(command: &Command) -> String {
-    let command = format!("{:?}", command);
-    let mut arg_iter = command.split_whitespace();
-    let command = arg_iter.next().unwrap();
-    let mut output = String::from(&command[1..command.len()-1]);
-    for argument in arg_iter {
-        output.push(' ');
-        if argument.len() > 2 {
-            output.push_str(&argument[1..argument.len()-1]);
-        } else {
-            output.push_str(&argument);
+/// Non-blocking-reaps any of `children` that have already exited, removing
+/// each from `children` and from `shell.foreground` (the same bookkeeping
+/// `watch_foreground` does for a normally-reaped pid), and reports what
+/// happened to `last_pid` this pass, if anything.
+fn reap_finished(shell: &mut Shell, children: &mut Vec<u32>, last_pid: u32) -> Option<LastPid> {
+    let mut outcome = None;
+    children.retain(|&pid| {
+        match sys::waitpid(pid as i32, true) {
+            Ok(Some(status)) => {
+                shell.foreground.retain(|&fg| fg != pid);
+                if pid == last_pid { outcome = Some(LastPid::Exited(status)); }
+                false
+            },
+            Ok(None) => true,
+            Err(_) => {
+                shell.foreground.retain(|&fg| fg != pid);
+                if pid == last_pid { outcome = Some(LastPid::Gone); }
+                false
+            },
         }
+    });
+    outcome
+}
+
+/// Blocking-reaps every pid still in `children`, draining the list. Used once
+/// a pipeline is known to be finishing (either `last_pid` already exited, or
+/// the group has just been `SIGKILL`ed) to make sure no stage is left a
+/// zombie even if it hasn't exited yet.
+fn reap_remaining(shell: &mut Shell, children: &mut Vec<u32>) {
+    for pid in children.drain(..) {
+        let _ = sys::waitpid(pid as i32, false);
+        shell.foreground.retain(|&fg| fg != pid);
+    }
+}
+
+/// Polls every process in the pipeline's group with a non-blocking `waitpid`
+/// until either `last_pid` exits or `deadline` passes. On expiry the group is
+/// sent `SIGTERM`, given a short grace period to exit, and `SIGKILL`ed if it
+/// is still alive, mirroring subprocess's `wait_timeout`/`terminate`/`kill`
+/// escalation. Every pid in `children` is reaped before returning, not just
+/// `last_pid`, so earlier pipeline stages don't end up as permanent zombies
+/// when the pipeline is killed for exceeding its deadline.
+///
+/// Untested here: exercising the escalation end-to-end needs a real `Shell`
+/// (for `foreground_send`) and real child processes, neither of which this
+/// module's existing pure-logic test style covers.
+fn wait_with_deadline(shell: &mut Shell, mut children: Vec<u32>, last_pid: u32, deadline: Instant) -> i32 {
+    const POLL_INTERVAL: Duration = Duration::from_millis(20);
+    const GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+    loop {
+        match reap_finished(shell, &mut children, last_pid) {
+            Some(LastPid::Exited(status)) => {
+                reap_remaining(shell, &mut children);
+                return status;
+            },
+            Some(LastPid::Gone) => {
+                // last_pid exited before the deadline but its status couldn't
+                // be retrieved (e.g. it was already reaped elsewhere via a
+                // double-reap race) - it did not time out, so don't report
+                // TIMED_OUT for a pipeline that actually finished.
+                reap_remaining(shell, &mut children);
+                return FAILURE;
+            },
+            None => {},
+        }
+        if Instant::now() >= deadline { break; }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    shell.foreground_send(sys::SIGTERM);
+    let grace_deadline = Instant::now() + GRACE_PERIOD;
+    while !children.is_empty() && Instant::now() < grace_deadline {
+        reap_finished(shell, &mut children, last_pid);
+        if !children.is_empty() { thread::sleep(POLL_INTERVAL); }
     }
-    output
+
+    if !children.is_empty() {
+        shell.foreground_send(sys::SIGKILL);
+    }
+    reap_remaining(shell, &mut children);
+    TIMED_OUT
 }
 
 #[cfg(test)]
@@ -351,7 +868,15 @@ mod tests {
     use shell::{Job, JobKind};
     use types::*;
     use parser::peg::Pipeline;
-    use super::check_if_background_job;
+    use std::io::{Read, Write};
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+    use std::time::Duration;
+    use sys;
+    use super::{check_if_background_job, communicate, full_command, looks_like_fd_redirect,
+                looks_like_process_substitution, parse_timeout_arg, quote, run_redirects,
+                RedirectTarget};
 
     #[test]
     fn single_job() {
@@ -370,4 +895,139 @@ mod tests {
         assert!(result.is_some());
         assert!(result.unwrap_or("".into()) == "true &");
     }
+
+    #[test]
+    fn quote_passes_through_plain_args() {
+        assert_eq!(quote("foo"), "foo");
+        assert_eq!(quote("foo.txt"), "foo.txt");
+    }
+
+    #[test]
+    fn quote_wraps_args_needing_it() {
+        assert_eq!(quote("foo bar"), "'foo bar'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn full_command_quotes_each_arg() {
+        let args = vec!["echo".to_string(), "hello world".to_string()];
+        assert_eq!(full_command(&args), "echo 'hello world'");
+    }
+
+    #[test]
+    fn run_redirects_applies_in_order() {
+        // Like `2>&1 1>file`, the outcome depends on order: closing `source`
+        // and then redirecting it elsewhere only lands on the second target if
+        // the two run in the
+        // order they were written. Reversed, the close would instead
+        // clobber the redirect that was supposed to win.
+        unsafe {
+            let (ra, wa) = sys::pipe2(sys::O_CLOEXEC).unwrap();
+            let (rb, wb) = sys::pipe2(sys::O_CLOEXEC).unwrap();
+            let source = wa;
+
+            run_redirects(&[(source, RedirectTarget::Close), (source, RedirectTarget::Fd(wb))]);
+
+            let mut writer = File::from_raw_fd(source);
+            writer.write_all(b"hi").unwrap();
+            drop(writer);
+            sys::close(wb).unwrap();
+
+            let mut got = Vec::new();
+            File::from_raw_fd(rb).read_to_end(&mut got).unwrap();
+            assert_eq!(got, b"hi");
+
+            // `ra` never received anything once `source` was redirected away
+            // from it; drop it without reading to avoid blocking on an empty
+            // still-open pipe.
+            sys::close(ra).unwrap();
+        }
+    }
+
+    #[test]
+    fn communicate_drains_payload_larger_than_pipe_buffer_without_deadlock() {
+        // Pushes well past the OS pipe buffer (~64KiB) through both stdin and
+        // stdout and checks that every byte arrives. The stand-in child ends
+        // below are independent threads, so this does not cover a child that
+        // blocks on one pipe until communicate() services the other.
+        const SIZE: usize = 256 * 1024;
+        unsafe {
+            let (stdout_r, stdout_w) = sys::pipe2(sys::O_CLOEXEC).unwrap();
+            let (stderr_r, stderr_w) = sys::pipe2(sys::O_CLOEXEC).unwrap();
+            let (stdin_r, stdin_w) = sys::pipe2(sys::O_CLOEXEC).unwrap();
+
+            let stdout_payload = vec![b'o'; SIZE];
+            let stdin_payload = vec![b'i'; SIZE];
+
+            // Stands in for a child writing more to stdout than fits in the
+            // pipe buffer, then closing it (EOF).
+            let stdout_payload_clone = stdout_payload.clone();
+            let stdout_writer = thread::spawn(move || {
+                let mut f = File::from_raw_fd(stdout_w);
+                f.write_all(&stdout_payload_clone).unwrap();
+            });
+            // No stderr output; close immediately so communicate() sees EOF.
+            sys::close(stderr_w).unwrap();
+            // Stands in for a child draining its stdin concurrently, so
+            // communicate()'s writes into stdin_w can make progress.
+            let stdin_reader = thread::spawn(move || {
+                let mut got = Vec::new();
+                File::from_raw_fd(stdin_r).read_to_end(&mut got).unwrap();
+                got
+            });
+
+            let stdin_pipe = Some((File::from_raw_fd(stdin_w), stdin_payload.clone()));
+            let stdout_file = File::from_raw_fd(stdout_r);
+            let stderr_file = File::from_raw_fd(stderr_r);
+
+            let (out, err) = communicate(stdin_pipe, stdout_file, stderr_file).unwrap();
+
+            stdout_writer.join().unwrap();
+            let received_stdin = stdin_reader.join().unwrap();
+
+            assert_eq!(out, stdout_payload);
+            assert!(err.is_empty());
+            assert_eq!(received_stdin, stdin_payload);
+        }
+    }
+
+    #[test]
+    fn parse_timeout_arg_parses_seconds() {
+        assert_eq!(parse_timeout_arg("5"), Ok(Some(Duration::from_secs(5))));
+        assert_eq!(parse_timeout_arg("0"), Ok(Some(Duration::from_secs(0))));
+    }
+
+    #[test]
+    fn parse_timeout_arg_off_disables() {
+        assert_eq!(parse_timeout_arg("off"), Ok(None));
+    }
+
+    #[test]
+    fn parse_timeout_arg_rejects_garbage() {
+        assert!(parse_timeout_arg("soon").is_err());
+        assert!(parse_timeout_arg("-1").is_err());
+    }
+
+    #[test]
+    fn looks_like_fd_redirect_matches_known_shapes() {
+        assert!(looks_like_fd_redirect("2>&1"));
+        assert!(looks_like_fd_redirect("3>file"));
+        assert!(looks_like_fd_redirect("4>&-"));
+        assert!(looks_like_fd_redirect("1>>file"));
+    }
+
+    #[test]
+    fn looks_like_fd_redirect_ignores_plain_words() {
+        assert!(!looks_like_fd_redirect("file.txt"));
+        assert!(!looks_like_fd_redirect("2"));
+        assert!(!looks_like_fd_redirect("http://example.com"));
+    }
+
+    #[test]
+    fn looks_like_process_substitution_matches_known_shapes() {
+        assert!(looks_like_process_substitution("<(sort a)"));
+        assert!(looks_like_process_substitution(">(tee log)"));
+        assert!(!looks_like_process_substitution("(a)"));
+    }
 }